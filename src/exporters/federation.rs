@@ -0,0 +1,489 @@
+//! # FederationExporter
+//!
+//! `FederationExporter` implementation. Periodically scrapes a list of
+//! remote scaphandre Prometheus endpoints, relabels each series with the
+//! target it came from, and re-exposes the merged result on its own
+//! `/metrics` endpoint. This lets a single collector aggregate the power
+//! consumption of a whole fleet of hypervisors without running a full-blown
+//! Prometheus server in front of it.
+use crate::exporters::Exporter;
+use chrono::Utc;
+use clap::{Arg, ArgMatches};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::{net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use hyper::{Body, Client, Request, Response, Server, Uri};
+use hyper::service::{make_service_fn, service_fn};
+
+/// Default ipv4/ipv6 address to expose the aggregated metrics on.
+const DEFAULT_IP_ADDRESS: &str = "::";
+
+/// Name of the gauge used to report whether a target was reachable on the
+/// last scrape, labeled with `instance`.
+const SCRAPE_UP_METRIC_NAME: &str = "scaphandre_scrape_up";
+
+/// A single parsed Prometheus/OpenMetrics sample line: a metric name, its
+/// label set and its value. Timestamps, if present, are ignored.
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// Exporter that scrapes remote scaphandre `PrometheusExporter` endpoints and
+/// re-exposes their union, relabeled per target, on its own endpoint.
+pub struct FederationExporter {}
+
+impl FederationExporter {
+    /// Instantiates and returns a new FederationExporter.
+    pub fn new() -> FederationExporter {
+        FederationExporter {}
+    }
+}
+
+impl Default for FederationExporter {
+    fn default() -> Self {
+        FederationExporter::new()
+    }
+}
+
+impl Exporter for FederationExporter {
+    /// Entry point of the FederationExporter.
+    fn run(&mut self, parameters: ArgMatches) {
+        info!(
+            "{}: Starting federation exporter",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S")
+        );
+        println!("Press CTRL-C to stop scaphandre");
+
+        let targets: Vec<String> = parameters
+            .value_of("targets")
+            .unwrap()
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+
+        runner(
+            targets,
+            parameters.value_of("address").unwrap().to_string(),
+            parameters.value_of("port").unwrap().to_string(),
+            parameters.value_of("suffix").unwrap().to_string(),
+            parameters
+                .value_of("scrape-timeout")
+                .unwrap()
+                .parse()
+                .expect("scrape-timeout should be a valid number of seconds"),
+            parameters
+                .value_of("scrape-interval")
+                .unwrap()
+                .parse()
+                .expect("scrape-interval should be a valid number of seconds"),
+        );
+    }
+
+    /// Returns options understood by the exporter.
+    fn get_options() -> Vec<clap::Arg<'static, 'static>> {
+        let mut options = Vec::new();
+        let arg = Arg::with_name("address")
+            .default_value(DEFAULT_IP_ADDRESS)
+            .help("ipv6 or ipv4 address to expose the service to")
+            .long("address")
+            .short("a")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("port")
+            .default_value("8080")
+            .help("TCP port number to expose the service")
+            .long("port")
+            .short("p")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("suffix")
+            .default_value("metrics")
+            .help("url suffix to access metrics")
+            .long("suffix")
+            .short("s")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("targets")
+            .help("Comma-separated list of remote scaphandre Prometheus exporter urls to scrape, e.g. http://host1:8080/metrics,http://host2:8080/metrics")
+            .long("targets")
+            .short("t")
+            .required(true)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("scrape-timeout")
+            .default_value("2")
+            .help("Timeout, in seconds, for each remote target scrape")
+            .long("scrape-timeout")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("scrape-interval")
+            .default_value("15")
+            .help("Interval, in seconds, between two scrape rounds of all targets")
+            .long("scrape-interval")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        options
+    }
+}
+
+/// Holds the body (formatted for exposition) produced by the last scrape
+/// round, so that serving a request is just returning a clone of a string.
+struct FederatedMetrics {
+    suffix: String,
+    last_body: Mutex<String>,
+}
+
+#[tokio::main]
+async fn runner(targets: Vec<String>, address: String, port: String, suffix: String, scrape_timeout_secs: u64, scrape_interval_secs: u64) {
+    let Ok(addr) = address.parse::<IpAddr>() else {
+        panic!("{} is not a valid ip address", address);
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        panic!("{} is not a valid TCP port number", port);
+    };
+    let socket_addr = SocketAddr::new(addr, port);
+
+    let context = Arc::new(FederatedMetrics {
+        suffix,
+        last_body: Mutex::new(String::new()),
+    });
+
+    let scrape_timeout = Duration::from_secs(scrape_timeout_secs);
+    let scrape_interval = Duration::from_secs(scrape_interval_secs);
+    let scrape_targets = targets.clone();
+    let scrape_context = context.clone();
+    tokio::spawn(async move {
+        loop {
+            let body = scrape_and_render(&scrape_targets, scrape_timeout).await;
+            *scrape_context.last_body.lock().unwrap() = body;
+            tokio::time::sleep(scrape_interval).await;
+        }
+    });
+
+    let make_svc = make_service_fn(move |_| {
+        let context = context.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| show_metrics(req, context.clone()))) }
+    });
+    let server = Server::bind(&socket_addr);
+    let res = server.serve(make_svc);
+
+    if let Err(e) = res.await {
+        error!("server error: {}", e);
+    }
+}
+
+/// All series accumulated so far for one metric name: its `# HELP`/`# TYPE`
+/// lines (taken from whichever target's response supplied them first) and
+/// every rendered sample line belonging to it, in first-seen order.
+///
+/// Prometheus/OpenMetrics exposition requires each metric family (its
+/// HELP/TYPE lines plus all of its samples) to appear exactly once and
+/// contiguously, even when the family is fed by several scraped targets, so
+/// samples are grouped by name here rather than written as each target is
+/// scraped.
+#[derive(Default)]
+struct MetricFamily {
+    help: Option<String>,
+    type_line: Option<String>,
+    samples: Vec<String>,
+}
+
+/// Returns the family for `name`, in `families`/`index`, creating an empty
+/// one (and recording its position) the first time `name` is seen.
+fn family_for<'a>(
+    families: &'a mut Vec<(String, MetricFamily)>,
+    index: &mut HashMap<String, usize>,
+    name: &str,
+) -> &'a mut MetricFamily {
+    let i = *index.entry(name.to_string()).or_insert_with(|| {
+        families.push((name.to_string(), MetricFamily::default()));
+        families.len() - 1
+    });
+    &mut families[i].1
+}
+
+/// Scrapes every target in `targets` (with a per-target `timeout`), parses
+/// each response, relabels every sample with the `instance` it came from,
+/// and renders the merged set plus one `scaphandre_scrape_up` gauge per
+/// target back into Prometheus text exposition format, with each metric
+/// family's HELP/TYPE lines and samples emitted exactly once and
+/// contiguously.
+async fn scrape_and_render(targets: &[String], timeout: Duration) -> String {
+    let client = Client::new();
+    let mut families: Vec<(String, MetricFamily)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for target in targets {
+        let instance = target_instance_label(target);
+        let up = match scrape_target(&client, target, timeout).await {
+            Ok(text) => {
+                append_relabeled_samples(&mut families, &mut index, &text, &instance);
+                1.0
+            }
+            Err(err) => {
+                warn!("Failed to scrape federation target {}: {}", target, err);
+                0.0
+            }
+        };
+        let family = family_for(&mut families, &mut index, SCRAPE_UP_METRIC_NAME);
+        family.help.get_or_insert_with(|| format!(
+            "# HELP {SCRAPE_UP_METRIC_NAME} Whether the last scrape of this target succeeded (1) or not (0)."
+        ));
+        family.type_line.get_or_insert_with(|| format!("# TYPE {SCRAPE_UP_METRIC_NAME} gauge"));
+        family.samples.push(format!("{SCRAPE_UP_METRIC_NAME}{{instance=\"{instance}\"}} {up}"));
+    }
+
+    render_families(&families)
+}
+
+/// Writes every accumulated family as one HELP line, one TYPE line and all
+/// of its samples, in the order families were first seen.
+fn render_families(families: &[(String, MetricFamily)]) -> String {
+    let mut body = String::new();
+    for (_, family) in families {
+        if let Some(help) = &family.help {
+            body.push_str(help);
+            body.push('\n');
+        }
+        if let Some(type_line) = &family.type_line {
+            body.push_str(type_line);
+            body.push('\n');
+        }
+        for sample in &family.samples {
+            body.push_str(sample);
+            body.push('\n');
+        }
+    }
+    body
+}
+
+/// Performs a single HTTP GET of `target`, bounded by `timeout`, and returns
+/// the response body as text.
+async fn scrape_target(client: &Client<hyper::client::HttpConnector>, target: &str, timeout: Duration) -> Result<String, String> {
+    let uri: Uri = target.parse().map_err(|e| format!("invalid url: {e}"))?;
+    let fut = async {
+        let response = client.get(uri).await.map_err(|e| e.to_string())?;
+        hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())
+    };
+    let bytes = tokio::time::timeout(timeout, fut)
+        .await
+        .map_err(|_| "timed out".to_string())??;
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Derives the `instance` label for a target from its url, e.g.
+/// `http://10.0.0.1:8080/metrics` becomes `10.0.0.1:8080`.
+fn target_instance_label(target: &str) -> String {
+    target
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|a| a.to_string()))
+        .unwrap_or_else(|| target.to_string())
+}
+
+/// Parses the Prometheus text exposition format in `text` and folds every
+/// HELP/TYPE line and sample into `families`/`index`, with an extra
+/// `instance` label added to each sample's label set (any pre-existing
+/// `instance` label on the sample is kept under `exported_instance` instead
+/// of being overwritten or duplicated).
+///
+/// HELP/TYPE lines are recorded under the metric name they declare (the
+/// second token, per the exposition format) instead of being copied
+/// verbatim into the output, so that scraping several targets exporting the
+/// same metric doesn't duplicate its HELP/TYPE lines or interleave its
+/// samples with those of other families. Samples whose own name has no
+/// HELP/TYPE lines yet are filed under [base_family_name], so a histogram's or
+/// summary's `_bucket`/`_sum`/`_count` series land in the same family as its
+/// HELP/TYPE lines instead of three separate, untyped ones.
+fn append_relabeled_samples(
+    families: &mut Vec<(String, MetricFamily)>,
+    index: &mut HashMap<String, usize>,
+    text: &str,
+    instance: &str,
+) {
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            if let Some((name, _)) = rest.split_once(' ') {
+                family_for(families, index, name).help.get_or_insert_with(|| line.to_string());
+            }
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            if let Some((name, _)) = rest.split_once(' ') {
+                family_for(families, index, name).type_line.get_or_insert_with(|| line.to_string());
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(sample) = parse_sample_line(line) {
+            let mut rendered = String::new();
+            rendered.push_str(&sample.name);
+            rendered.push('{');
+            rendered.push_str(&format!("instance=\"{instance}\""));
+            for (k, v) in &sample.labels {
+                // The target already carries its own `instance` label (e.g.
+                // a federation target re-exposing another one): keep ours
+                // (the target we actually scraped) and preserve theirs under
+                // a renamed key instead of emitting `instance` twice, which
+                // would be invalid exposition.
+                let key = if k == "instance" { "exported_instance" } else { k.as_str() };
+                rendered.push_str(&format!(",{key}=\"{}\"", escape_label_value(v)));
+            }
+            rendered.push_str(&format!("}} {}", sample.value));
+            let family_name = if index.contains_key(&sample.name) {
+                sample.name.clone()
+            } else {
+                base_family_name(&sample.name).to_string()
+            };
+            family_for(families, index, &family_name).samples.push(rendered);
+        }
+    }
+}
+
+/// Strips the `_bucket`/`_sum`/`_count`/`_created` suffix OpenMetrics adds
+/// to a histogram's or summary's individual series, resolving them back to
+/// the base metric name their shared `# HELP`/`# TYPE` lines are filed
+/// under, so all of a histogram's series render contiguously with it
+/// instead of as separate, untyped families.
+fn base_family_name(name: &str) -> &str {
+    for suffix in ["_bucket", "_sum", "_count", "_created"] {
+        if let Some(base) = name.strip_suffix(suffix) {
+            return base;
+        }
+    }
+    name
+}
+
+/// Parses one exposition line of the form `name{label="value",...} value`,
+/// respecting OpenMetrics/Prometheus label-value quoting (backslash-escaped
+/// `"`, `\` and `n`) so that values containing spaces or commas - such as
+/// scaphandre's `exe`/`cmdline` process labels - are parsed as a single
+/// label rather than split on whitespace or `,`.
+fn parse_sample_line(line: &str) -> Option<Sample> {
+    let (name, labels, rest) = if let Some(brace) = line.find('{') {
+        let name = line[..brace].to_string();
+        let (labels, rest) = parse_label_set(&line[brace + 1..])?;
+        (name, labels, rest)
+    } else {
+        let mut tokens = line.splitn(2, char::is_whitespace);
+        let name = tokens.next()?.to_string();
+        (name, vec![], tokens.next()?)
+    };
+
+    let value = rest.trim_start().split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(Sample { name, labels, value })
+}
+
+/// Parses a `key1="value1",key2="value2"}` label set starting just after
+/// the opening `{`, honoring quoting and backslash escapes inside values.
+/// Returns the parsed labels along with whatever follows the closing `}`
+/// (the sample's value and optional timestamp).
+fn parse_label_set(input: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let mut labels = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    loop {
+        while matches!(chars.peek(), Some((_, c)) if c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        match chars.peek() {
+            Some((i, '}')) => {
+                let i = *i;
+                return Some((labels, &input[i + 1..]));
+            }
+            None => return None,
+            _ => {}
+        }
+
+        let mut key = String::new();
+        while let Some(&(_, c)) = chars.peek() {
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        chars.next()?; // consume '='
+        if chars.next()?.1 != '"' {
+            return None;
+        }
+
+        let mut value = String::new();
+        loop {
+            let (_, c) = chars.next()?;
+            match c {
+                '\\' => {
+                    let (_, escaped) = chars.next()?;
+                    value.push(match escaped {
+                        'n' => '\n',
+                        other => other,
+                    });
+                }
+                '"' => break,
+                other => value.push(other),
+            }
+        }
+        labels.push((key.trim().to_string(), value));
+    }
+}
+
+/// Escapes a label value for re-exposition, inverse of the unescaping done
+/// in [parse_label_set].
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            other => escaped.push(other),
+        }
+    }
+    escaped
+}
+
+/// Handles requests and returns the merged metrics from the last scrape round.
+async fn show_metrics(req: Request<Body>, context: Arc<FederatedMetrics>) -> Result<Response<Body>, Infallible> {
+    let expected_path = format!("/{}", context.suffix);
+    if req.uri().path() != expected_path {
+        return Ok(Response::new(Body::from(format!(
+            "scaphandre federation exporter here. Metrics available on {expected_path}"
+        ))));
+    }
+    Ok(Response::new(Body::from(context.last_body.lock().unwrap().clone())))
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
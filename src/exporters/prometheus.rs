@@ -4,19 +4,70 @@
 //! a [Prometheus](https://prometheus.io/) server.
 use crate::current_system_time_since_epoch;
 use crate::sensors::{Sensor, Topology};
-use crate::exporters::Exporter;
+use crate::exporters::{Exporter, MetricGenerator, MetricValueType};
+use crate::exporters::reload::{self, ReloadableConfig};
 use chrono::Utc;
 use clap::{Arg, ArgMatches};
-use std::{collections::HashMap, net::{IpAddr, SocketAddr}, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
 use super::utils::get_hostname;
 use std::convert::Infallible;
 use hyper::{Body, Request, Response, Server};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::server::conn::AddrStream;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use tokio::sync::oneshot;
 
 /// Default ipv4/ipv6 address to expose the service is any
 const DEFAULT_IP_ADDRESS: &str = "::";
 
+/// Minimum duration between two topology refreshes, to avoid hammering the
+/// sensor if Prometheus (or anything else) scrapes `/metrics` aggressively.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Name of the gauge metric generated by `MetricGenerator` for the host's
+/// current dynamic power draw, in microwatts. Also observed into a
+/// histogram to expose its distribution.
+const HOST_POWER_METRIC_NAME: &str = "scaph_host_power_microwatts";
+
+/// Name of the gauge metric generated by `MetricGenerator` for a process'
+/// current dynamic power draw, in microwatts. Also observed into a
+/// histogram to expose its distribution.
+const PROCESS_POWER_METRIC_NAME: &str = "scaph_process_power_consumption_microwatts";
+
+/// Labels a given metric carries, in declaration order, converted from the
+/// dynamic `HashMap<String, String>` that `MetricGenerator` produces.
+type DynamicLabels = Vec<(String, String)>;
+
+/// Boundaries of the exponential buckets used for the power-distribution
+/// histograms, as `(start, factor, count)`: bucket `i` has the upper bound
+/// (`le`) `start * factor.powi(i)`.
+#[derive(Clone, Copy)]
+struct HistogramBuckets {
+    start: f64,
+    factor: f64,
+    count: usize,
+}
+
+impl HistogramBuckets {
+    fn boundaries(&self) -> impl Iterator<Item = f64> {
+        exponential_buckets(self.start, self.factor, self.count)
+    }
+}
+
 /// Exporter that exposes metrics to an HTTP endpoint
 /// matching the Prometheus.io metrics format.
 pub struct PrometheusExporter {
@@ -43,6 +94,24 @@ impl Exporter for PrometheusExporter {
         );
         println!("Press CTRL-C to stop scaphandre");
 
+        let histogram_buckets = HistogramBuckets {
+            start: parameters
+                .value_of("histogram-buckets-start")
+                .unwrap()
+                .parse()
+                .expect("histogram-buckets-start should be a valid number"),
+            factor: parameters
+                .value_of("histogram-buckets-factor")
+                .unwrap()
+                .parse()
+                .expect("histogram-buckets-factor should be a valid number"),
+            count: parameters
+                .value_of("histogram-buckets-count")
+                .unwrap()
+                .parse()
+                .expect("histogram-buckets-count should be a valid integer"),
+        };
+
         runner(
             (*self.sensor.get_topology()).unwrap(),
             parameters.value_of("address").unwrap().to_string(),
@@ -51,6 +120,8 @@ impl Exporter for PrometheusExporter {
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
             get_hostname(),
+            histogram_buckets,
+            parameters.value_of("config-file").map(PathBuf::from),
         );
     }
     /// Returns options understood by the exporter.
@@ -98,210 +169,322 @@ impl Exporter for PrometheusExporter {
             .takes_value(false);
         options.push(arg);
 
+        let arg = Arg::with_name("histogram-buckets-start")
+            .default_value("1.0")
+            .help("Upper bound (in watts) of the first power-distribution histogram bucket")
+            .long("histogram-buckets-start")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("histogram-buckets-factor")
+            .default_value("2.0")
+            .help("Multiplication factor between two consecutive power-distribution histogram buckets")
+            .long("histogram-buckets-factor")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("histogram-buckets-count")
+            .default_value("16")
+            .help("Number of buckets in the power-distribution histograms")
+            .long("histogram-buckets-count")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("config-file")
+            .help("Path to a JSON config file (address, port, suffix, qemu, containers) watched for live reload on change or SIGHUP")
+            .long("config-file")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
         options
     }
 }
 
-/// Contains a mutex holding a Topology object.
-/// Used to pass the topology data from one http worker to another.
+/// Shared state and registry passed to every request handler. Holds the
+/// `Topology` behind a mutex so each scrape can refresh it in place, and one
+/// `Family` of gauges per metric name registered so far, so the set of
+/// metrics can grow as `MetricGenerator` discovers new processes/domains.
+/// `gauges`' label sets are cleared and repopulated on every scrape (see
+/// [PowerMetrics::refresh_and_update_metrics]) so that a process/domain that
+/// disappeared is also pruned from the exposition, instead of accumulating
+/// unbounded label cardinality; `process_power_histogram` is pruned the same
+/// way but without clearing its surviving series' accumulated buckets.
+///
+/// `qemu`, `containers` and `suffix` are stored behind atomics/a `RwLock`
+/// rather than plain fields so a config reload can flip them in place while
+/// requests are being served, without touching `topology` (and thus without
+/// resetting its monotonic counters or `proc_tracker` history).
 struct PowerMetrics {
-    topology: Topology,
-    last_request: Duration,
-    qemu: bool,
-    containers: bool,
+    topology: Mutex<Topology>,
+    last_request: Mutex<Duration>,
+    qemu: AtomicBool,
+    containers: AtomicBool,
     hostname: String,
+    suffix: RwLock<String>,
+    registry: Mutex<Registry>,
+    gauges: Mutex<HashMap<String, Family<DynamicLabels, Gauge<f64, std::sync::atomic::AtomicU64>>>>,
+    host_power_histogram: Histogram,
+    /// One histogram per process label set. Unlike `gauges`, this is *not*
+    /// cleared on every scrape: each bucket accumulates a distribution over
+    /// time, so wiping it every request would reset every process to a
+    /// single observation and defeat the point of exposing a distribution.
+    /// Only the label sets of processes no longer present are pruned (see
+    /// [PowerMetrics::refresh_and_update_metrics]), so a live process keeps
+    /// its accumulated buckets while a terminated one stops being exported.
+    process_power_histogram: Family<DynamicLabels, Histogram>,
+    /// Process label sets observed into `process_power_histogram` on the
+    /// last real topology refresh, used to detect which ones disappeared
+    /// and should be pruned from the histogram.
+    process_labels_seen: Mutex<HashSet<DynamicLabels>>,
 }
 
-#[tokio::main]
-async fn runner(
-    topology: Topology, address: String, port: String, suffix: String, qemu: bool, containers: bool, hostname: String,
-){
-    if let Ok(addr) = address.parse::<IpAddr>() {
-        if let Ok(port) = port.parse::<u16>() {
-            let socket_addr = SocketAddr::new(addr, port);
-            let context = Arc::new(PowerMetrics {
-                topology: topology.clone(),
-                last_request: Duration::new(0, 0),
-                qemu,
-                containers,
-                hostname: hostname.clone(),
-            });
-            let make_svc = make_service_fn(move |_| {
-                async {
-                    Ok::<_, Infallible>(
-                            service_fn( move |req| {
-                                show_metrics(req)
-                            }
-                        )
-                    )
+impl PowerMetrics {
+    /// Returns the `Family` registered for `name`, registering a new one with
+    /// `registry` (and `help`) the first time this metric name is seen.
+    fn gauge_for(&self, name: &str, help: &str) -> Family<DynamicLabels, Gauge<f64, std::sync::atomic::AtomicU64>> {
+        let mut gauges = self.gauges.lock().unwrap();
+        if let Some(family) = gauges.get(name) {
+            return family.clone();
+        }
+        let family = Family::default();
+        self.registry
+            .lock()
+            .unwrap()
+            .register(name, help, family.clone());
+        gauges.insert(name.to_string(), family.clone());
+        family
+    }
+
+    /// Refreshes the topology if more than [MIN_REFRESH_INTERVAL] elapsed
+    /// since the previous request (whether or not that one refreshed it), then
+    /// clears every gauge label set previously
+    /// exported and repopulates them from a fresh `MetricGenerator` pass.
+    ///
+    /// Clearing gauges before repopulating (rather than just overwriting) is
+    /// what makes a process/domain that disappeared between two scrapes
+    /// actually disappear from the exposition too, instead of being exported
+    /// forever at its last known value.
+    ///
+    /// The power-distribution histograms are only observed - and pruned of
+    /// label sets no longer present - when this call actually refreshed the
+    /// topology, not on every request: a request arriving faster than
+    /// [MIN_REFRESH_INTERVAL] reads the same unchanged topology, and
+    /// observing it again would inflate the distribution with repeats of the
+    /// same measurement rather than recording a new one.
+    fn refresh_and_update_metrics(&self) {
+        let now = current_system_time_since_epoch();
+        let mut last_request = self.last_request.lock().unwrap();
+        let did_refresh = now - *last_request > MIN_REFRESH_INTERVAL;
+        if did_refresh {
+            info!(
+                "{}: Refresh topology",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            let mut topology = self.topology.lock().unwrap();
+            topology.proc_tracker.clean_terminated_process_records_vectors();
+            topology.refresh();
+        }
+        *last_request = now;
+
+        let topology = self.topology.lock().unwrap();
+        let mut metric_generator = MetricGenerator::new(&topology, &self.hostname);
+        metric_generator.gen_all_metrics(
+            self.qemu.load(Ordering::Relaxed),
+            self.containers.load(Ordering::Relaxed),
+        );
+
+        for family in self.gauges.lock().unwrap().values() {
+            family.clear();
+        }
+
+        let mut process_labels_this_round = HashSet::new();
+        for msg in metric_generator.get_metrics() {
+            let value = match &msg.metric_value {
+                MetricValueType::FloatDouble(value) => *value,
+                MetricValueType::IntUnsigned(value) => *value as f64,
+                MetricValueType::Text(value) => value.parse::<f64>().unwrap_or(0.0),
+            };
+            let labels: DynamicLabels = msg.attributes.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+            self.gauge_for(&msg.name, &msg.description)
+                .get_or_create(&labels)
+                .set(value);
+
+            if !did_refresh {
+                continue;
+            }
+            let watts = value / 1_000_000.0;
+            match msg.name.as_str() {
+                HOST_POWER_METRIC_NAME => self.host_power_histogram.observe(watts),
+                PROCESS_POWER_METRIC_NAME => {
+                    self.process_power_histogram.get_or_create(&labels).observe(watts);
+                    process_labels_this_round.insert(labels);
                 }
-            });
-            let server = Server::bind(&socket_addr);
-            let res = server.serve(make_svc);
+                _ => {}
+            }
+        }
 
-            if let Err(e) = res.await {
-                error!("server error: {}", e);
+        if did_refresh {
+            let mut process_labels_seen = self.process_labels_seen.lock().unwrap();
+            for stale in process_labels_seen.difference(&process_labels_this_round) {
+                self.process_power_histogram.remove(stale);
             }
-        } else {
-            panic!("{} is not a valid TCP port number", port);
+            *process_labels_seen = process_labels_this_round;
         }
-    } else {
-        panic!("{} is not a valid ip address", address);
     }
 }
 
-//#[actix_web::main]
-///// Main function running the HTTP server.
-//async fn runner(
-//    topology: Topology,
-//    address: String,
-//    port: String,
-//    suffix: String,
-//    qemu: bool,
-//    containers: bool,
-//    hostname: String,
-//) -> std::io::Result<()> {
-//    if let Err(error) = address.parse::<IpAddr>() {
-//        panic!("{} is not a valid ip address: {}", address, error);
-//    }
-//    if let Err(error) = port.parse::<u64>() {
-//        panic!("Not a valid TCP port numer: {}", error);
-//    }
-//
-//    HttpServer::new(move || {
-//        App::new()
-//            .data(PowerMetrics {
-//                topology: Mutex::new(topology.clone()),
-//                last_request: Mutex::new(Duration::new(0, 0)),
-//                qemu,
-//                containers,
-//                hostname: hostname.clone(),
-//            })
-//            .service(web::resource(&suffix).route(web::get().to(show_metrics)))
-//            .default_service(web::route().to(landing_page))
-//    })
-//    .workers(1)
-//    .bind(format!("{}:{}", address, port))?
-//    .run()
-//    .await
-//}
-//
-/// Returns a well formatted Prometheus metric string.
-fn format_metric(key: &str, value: &str, labels: Option<&HashMap<String, String>>) -> String {
-    let mut result = key.to_string();
-    if let Some(labels) = labels {
-        result.push('{');
-        for (k, v) in labels.iter() {
-            result.push_str(&format!("{}=\"{}\",", k, v));
+#[tokio::main]
+async fn runner(
+    topology: Topology, address: String, port: String, suffix: String, qemu: bool, containers: bool, hostname: String,
+    histogram_buckets: HistogramBuckets, config_file: Option<PathBuf>,
+){
+    let mut current_config = ReloadableConfig { address, port, suffix, qemu, containers };
+    let Ok(socket_addr) = socket_addr_from(&current_config) else {
+        return;
+    };
+
+    let mut registry = Registry::default();
+    let host_power_histogram = Histogram::new(histogram_buckets.boundaries());
+    let process_power_histogram =
+        Family::new_with_constructor(move || Histogram::new(histogram_buckets.boundaries()));
+    registry.register(
+        "scaph_host_power_distribution_watts",
+        "Distribution of the host's dynamic power consumption, in watts",
+        host_power_histogram.clone(),
+    );
+    registry.register(
+        "scaph_process_power_distribution_watts",
+        "Distribution of each process' dynamic power consumption, in watts",
+        process_power_histogram.clone(),
+    );
+    let context = Arc::new(PowerMetrics {
+        topology: Mutex::new(topology),
+        last_request: Mutex::new(Duration::new(0, 0)),
+        qemu: AtomicBool::new(current_config.qemu),
+        containers: AtomicBool::new(current_config.containers),
+        hostname,
+        suffix: RwLock::new(current_config.suffix.clone()),
+        registry: Mutex::new(registry),
+        gauges: Mutex::new(HashMap::new()),
+        host_power_histogram,
+        process_power_histogram,
+        process_labels_seen: Mutex::new(HashSet::new()),
+    });
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    tokio::spawn(serve(socket_addr, context.clone(), shutdown_rx));
+
+    let Some(config_file) = config_file else {
+        // No config file to watch: run forever, the process only stops on
+        // an external signal (Ctrl-C or an actual kill).
+        std::future::pending::<()>().await;
+        return;
+    };
+
+    let mut reload_rx = bridge_reload_channel(reload::watch(config_file));
+    let mut shutdown_tx = shutdown_tx;
+    while let Some(new_config) = reload_rx.recv().await {
+        context.qemu.store(new_config.qemu, Ordering::Relaxed);
+        context.containers.store(new_config.containers, Ordering::Relaxed);
+        *context.suffix.write().unwrap() = new_config.suffix.clone();
+
+        if current_config.needs_rebind(&new_config) {
+            let Ok(new_addr) = socket_addr_from(&new_config) else {
+                current_config = new_config;
+                continue;
+            };
+            info!("Listen address changed, rebinding Prometheus exporter to {new_addr}");
+            let (new_shutdown_tx, new_shutdown_rx) = oneshot::channel();
+            let _ = shutdown_tx.send(());
+            shutdown_tx = new_shutdown_tx;
+            tokio::spawn(serve(new_addr, context.clone(), new_shutdown_rx));
         }
-        result.remove(result.len() - 1);
-        result.push('}');
+        current_config = new_config;
     }
-    result.push_str(&format!(" {}\n", value));
-    result
 }
 
-/// Adds lines related to a metric in the body (String) of response.
-fn push_metric(
-    mut body: String,
-    help: String,
-    metric_type: String,
-    metric_name: String,
-    metric_line: String,
-) -> String {
-    body.push_str(&format!("# HELP {} {}", metric_name, help));
-    body.push_str(&format!("\n# TYPE {} {}\n", metric_name, metric_type));
-    body.push_str(&metric_line);
-    body
+/// Parses the `address`/`port` pair of a [ReloadableConfig] into a
+/// [SocketAddr], logging and returning `Err` instead of panicking, so that a
+/// bad value in a reloaded config doesn't bring the whole exporter down.
+fn socket_addr_from(config: &ReloadableConfig) -> Result<SocketAddr, ()> {
+    let Ok(addr) = config.address.parse::<IpAddr>() else {
+        error!("{} is not a valid ip address", config.address);
+        return Err(());
+    };
+    let Ok(port) = config.port.parse::<u16>() else {
+        error!("{} is not a valid TCP port number", config.port);
+        return Err(());
+    };
+    Ok(SocketAddr::new(addr, port))
 }
 
-//#[derive(Clone, Copy)]
-//struct Router {
-//
-//}
-
-//impl Router {
-    /// Handles requests and returns data formated for Prometheus.
-    async fn show_metrics(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        warn!("{}", req.uri());
-        let mut body = String::new();
-        if req.uri().path() == "/metrics" {
-            body.push_str("Here come tha metriczzz !!!");
-        } else {
-            body.push_str("go to /metrics !!");
+/// Bridges the blocking `std::sync::mpsc::Receiver` produced by
+/// [reload::watch] into a `tokio::sync::mpsc` channel that can be awaited on
+/// alongside the rest of the async runtime.
+fn bridge_reload_channel(
+    rx: std::sync::mpsc::Receiver<ReloadableConfig>,
+) -> tokio::sync::mpsc::UnboundedReceiver<ReloadableConfig> {
+    let (tx, async_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || {
+        while let Ok(config) = rx.recv() {
+            if tx.send(config).is_err() {
+                break;
+            }
         }
-        Ok(Response::new(body.into()))
+    });
+    async_rx
+}
+
+/// Binds and runs the HTTP server on `addr` until `shutdown_rx` fires.
+async fn serve(addr: SocketAddr, context: Arc<PowerMetrics>, shutdown_rx: oneshot::Receiver<()>) {
+    let make_svc = make_service_fn(move |_| {
+        let context = context.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| show_metrics(req, context.clone()))) }
+    });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_svc),
+        Err(e) => {
+            error!("Could not bind to {}: {}", addr, e);
+            return;
+        }
+    };
+    let graceful = server.with_graceful_shutdown(async {
+        let _ = shutdown_rx.await;
+    });
+    if let Err(e) = graceful.await {
+        error!("server error: {}", e);
     }
+}
+
+/// Handles requests and returns data formatted for Prometheus/OpenMetrics.
+async fn show_metrics(req: Request<Body>, context: Arc<PowerMetrics>) -> Result<Response<Body>, Infallible> {
+    let expected_path = format!("/{}", context.suffix.read().unwrap());
+    if req.uri().path() != expected_path {
+        return Ok(Response::new(Body::from(format!(
+            "<a href=\"https://github.com/hubblo-org/scaphandre/\">Scaphandre's</a> prometheus exporter here. Metrics available on <a href=\"{expected_path}\">{expected_path}</a>"
+        ))));
+    }
+
+    context.refresh_and_update_metrics();
+
+    let mut body = String::new();
+    if let Err(e) = encode(&mut body, &context.registry.lock().unwrap()) {
+        error!("failed to encode metrics: {}", e);
+    }
+
+    Ok(Response::builder()
+        .header(
+            hyper::header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )
+        .body(Body::from(body))
+        .unwrap())
+}
 
-//}
-//async fn show_metrics(context: Arc<PowerMetrics>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-//    Ok(Response::new("Coucou toi !".into()))
-//}
-//async fn show_metrics(data: web::Data<PowerMetrics>) -> impl Responder {
-//    let now = current_system_time_since_epoch();
-//    let mut last_request = data.last_request.lock().unwrap();
-//
-//    if now - (*last_request) > Duration::from_secs(5) {
-//        {
-//            info!(
-//                "{}: Refresh topology",
-//                Utc::now().format("%Y-%m-%dT%H:%M:%S")
-//            );
-//            let mut topology = data.topology.lock().unwrap();
-//            (*topology)
-//                .proc_tracker
-//                .clean_terminated_process_records_vectors();
-//            (*topology).refresh();
-//        }
-//    }
-//
-//    *last_request = now;
-//    let topo = data.topology.lock().unwrap();
-//    let mut metric_generator = MetricGenerator::new(&*topo, &data.hostname);
-//
-//    info!("{}: Refresh data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
-//    let mut body = String::from(""); // initialize empty body
-//
-//    metric_generator.gen_all_metrics(data.qemu, data.containers);
-//
-//    // Send all data
-//    for msg in metric_generator.get_metrics() {
-//        let mut attributes: Option<&HashMap<String, String>> = None;
-//        if !msg.attributes.is_empty() {
-//            attributes = Some(&msg.attributes);
-//        }
-//
-//        let value = match msg.metric_value {
-//            // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
-//            // MetricValueType::Float(value) => event.set_metric_f(value),
-//            MetricValueType::FloatDouble(value) => value.to_string(),
-//            MetricValueType::IntUnsigned(value) => value.to_string(),
-//            MetricValueType::Text(ref value) => value.to_string(),
-//        };
-//        body = push_metric(
-//            body,
-//            msg.description.clone(),
-//            msg.metric_type.clone(),
-//            msg.name.clone(),
-//            format_metric(&msg.name, &value, attributes),
-//        );
-//    }
-//
-//    HttpResponse::Ok()
-//        //.set_header("X-TEST", "value")
-//        .body(body)
-//}
-//
-///// Handles requests that are not asking for /metrics and returns the appropriate path in the body of the response.
-//async fn landing_page() -> impl Responder {
-//    let body = String::from(
-//        "<a href=\"https://github.com/hubblo-org/scaphandre/\">Scaphandre's</a> prometheus exporter here. Metrics available on <a href=\"/metrics\">/metrics</a>"
-//    );
-//    HttpResponse::Ok()
-//        //.set_header("X-TEST", "value")
-//        .body(body)
-//}
-//
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
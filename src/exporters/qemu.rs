@@ -1,9 +1,34 @@
+use crate::current_system_time_since_epoch;
 use crate::exporters::Exporter;
 use crate::sensors::Topology;
 use crate::sensors::{utils::ProcessRecord, Sensor};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::{fs, io, thread, time};
 
+mod qmp;
+use qmp::{QmpClient, QmpVcpu};
+
+/// Minimum interval between two QMP re-queries of the same guest's identity
+/// and vCPU thread mapping. Both are essentially immutable for the lifetime
+/// of the guest, so there's no need to re-handshake its monitor on every
+/// iteration; doing so for every running guest on every iteration is an
+/// O(guests²) handshake cost per scrape, and every extra connection to
+/// libvirt's own control monitor is a small risk of contending with
+/// libvirt's own use of it (see `QemuExporter::refresh_qmp_guest_cache`).
+const QMP_CACHE_TTL: time::Duration = time::Duration::from_secs(60);
+
+/// A guest's identity and vCPU thread mapping as last obtained over QMP,
+/// plus when it was obtained -- cached
+/// per QEMU pid so steady-state iterations don't need to re-discover and
+/// re-query every monitor on the host (see [QMP_CACHE_TTL]).
+struct CachedGuestInfo {
+    vm_name: String,
+    uuid: String,
+    vcpus: Vec<QmpVcpu>,
+    queried_at: time::Duration,
+}
+
 /// An Exporter that extracts power consumption data of running
 /// Qemu/KVM virtual machines on the host and store those data
 /// as folders and files that are supposed to be mounted on the
@@ -14,6 +39,13 @@ pub struct QemuExporter {
     // We don't need a MetricGenerator for this exporter, because it "justs"
     // puts the metrics in files in the same way as the powercap kernel module.
     topology: Topology,
+    // Previous (vcpu jiffies, host jiffies) reading for each guest we've seen
+    // over QMP, keyed by guest UUID, used to compute a tighter vCPU-thread-only
+    // attribution factor between two iterations.
+    vcpu_jiffies: HashMap<String, (u64, u64)>,
+    // Guest identity/vCPU mapping obtained over QMP, keyed by QEMU pid and
+    // refreshed only every [QMP_CACHE_TTL] (see [CachedGuestInfo]).
+    qmp_guest_cache: HashMap<i32, CachedGuestInfo>,
 }
 
 impl Exporter for QemuExporter {
@@ -49,7 +81,11 @@ impl QemuExporter {
         let topology = sensor
             .get_topology()
             .expect("sensor topology should be available");
-        QemuExporter { topology }
+        QemuExporter {
+            topology,
+            vcpu_jiffies: HashMap::new(),
+            qmp_guest_cache: HashMap::new(),
+        }
     }
 
     /// Processes the metrics of `self.topology` and exposes them at the given `path`.
@@ -70,15 +106,18 @@ impl QemuExporter {
         for qp in qemu_processes {
             if qp.len() > 2 {
                 let last = qp.first().unwrap();
-                let vm_name = QemuExporter::get_vm_name_from_cmdline(
-                    &last.process.cmdline(&self.topology.proc_tracker).unwrap(),
-                );
+                let (vm_name, proc_utilization) = match self.get_vm_info_via_qmp(last.process.pid) {
+                    Some((name, factor)) => (name, Some(factor)),
+                    None => (
+                        QemuExporter::get_vm_name_from_cmdline(
+                            &last.process.cmdline(&self.topology.proc_tracker).unwrap(),
+                        ),
+                        self.topology.get_process_attribution_factor(last.process.pid),
+                    ),
+                };
 
                 let exported_path = format!("{path}/{vm_name}");
-                if let Some(proc_utilization) = self
-                    .topology
-                    .get_process_attribution_factor(last.process.pid)
-                {
+                if let Some(proc_utilization) = proc_utilization {
                     let uj_to_add = proc_utilization * uj_dynamic;
                     match QemuExporter::add_or_create(&PathBuf::from(exported_path.clone()), uj_to_add as u64) {
                         Ok(result) => {
@@ -156,6 +195,136 @@ impl QemuExporter {
         fs::write(domain_core.join("energy_uj"), content.to_string())
     }
 
+    /// Looks up `qemu_pid`'s guest identity and vCPU thread mapping from
+    /// `qmp_guest_cache`, refreshing it over QMP first if it's missing or
+    /// older than [QMP_CACHE_TTL], and computes an attribution factor from
+    /// the CPU time spent in those vCPU threads alone (excluding QEMU's
+    /// emulator and IO threads). Returns `None` if no QMP monitor could be
+    /// matched to this process, or if no factor can be computed yet (the
+    /// first reading for this guest, or `/proc/stat` unreadable), in which
+    /// case the caller falls back to the cmdline name and the whole-process
+    /// attribution factor.
+    fn get_vm_info_via_qmp(&mut self, qemu_pid: i32) -> Option<(String, f64)> {
+        let now = current_system_time_since_epoch();
+        let is_stale = match self.qmp_guest_cache.get(&qemu_pid) {
+            Some(cached) => now.saturating_sub(cached.queried_at) > QMP_CACHE_TTL,
+            None => true,
+        };
+        if is_stale {
+            self.refresh_qmp_guest_cache(qemu_pid, now);
+        }
+
+        let cached = self.qmp_guest_cache.get(&qemu_pid)?;
+        let vm_name = cached.vm_name.clone();
+        let uuid = cached.uuid.clone();
+        let vcpus = cached.vcpus.clone();
+        self.compute_vcpu_attribution_factor(&uuid, &vcpus)
+            .map(|factor| (vm_name, factor))
+    }
+
+    /// Scans every discoverable QMP monitor socket for the one whose vCPU
+    /// threads belong to `qemu_pid`, and caches the match in
+    /// `qmp_guest_cache` (or forgets any stale entry if none is found).
+    ///
+    /// These sockets are libvirt's own control monitors
+    /// (`/var/lib/libvirt/qemu/<domain>/monitor.sock`), not a monitor
+    /// dedicated to scaphandre: libvirtd is the expected owner of each one
+    /// and treats it as its exclusive command channel, so a second client
+    /// reading/writing it (even just for these read-only queries) carries
+    /// some risk of contending with libvirt's own use of it. [QMP_CACHE_TTL]
+    /// keeps this to one short-lived connection per guest per refresh
+    /// instead of one per guest per iteration; a deployment that wants to
+    /// eliminate the risk entirely should give scaphandre its own QMP
+    /// monitor (e.g. a second `-qmp` socket on the guest) instead of
+    /// pointing it at libvirt's.
+    fn refresh_qmp_guest_cache(&mut self, qemu_pid: i32, now: time::Duration) {
+        for socket in qmp::discover_monitor_sockets(Path::new(qmp::DEFAULT_QMP_SOCKET_DIR)) {
+            let mut client = match QmpClient::connect(&socket) {
+                Ok(client) => client,
+                Err(err) => {
+                    trace!("Couldn't connect to QMP socket {}: {}", socket.display(), err);
+                    continue;
+                }
+            };
+            let info = match client.query_guest_info() {
+                Ok(Some(info)) => info,
+                _ => continue,
+            };
+            if info.vcpus.is_empty() || !QemuExporter::thread_belongs_to_process(qemu_pid, info.vcpus[0].thread_id) {
+                continue;
+            }
+            let vm_name = info.name.unwrap_or_else(|| info.uuid.clone());
+            self.qmp_guest_cache.insert(
+                qemu_pid,
+                CachedGuestInfo {
+                    vm_name,
+                    uuid: info.uuid,
+                    vcpus: info.vcpus,
+                    queried_at: now,
+                },
+            );
+            return;
+        }
+        self.qmp_guest_cache.remove(&qemu_pid);
+    }
+
+    /// Returns whether `tid` is one of the threads of `pid`, i.e. whether
+    /// `/proc/<pid>/task/<tid>` exists.
+    fn thread_belongs_to_process(pid: i32, tid: i32) -> bool {
+        Path::new(&format!("/proc/{pid}/task/{tid}")).exists()
+    }
+
+    /// Computes the share of host CPU time spent in `vcpus`' host threads
+    /// between the previous and the current call, as a ratio of the total
+    /// host CPU time spent over the same interval. The previous reading for
+    /// `guest_uuid` is kept in `self.vcpu_jiffies` across calls.
+    fn compute_vcpu_attribution_factor(&mut self, guest_uuid: &str, vcpus: &[QmpVcpu]) -> Option<f64> {
+        let vcpu_jiffies: u64 = vcpus
+            .iter()
+            .filter_map(|vcpu| QemuExporter::read_thread_jiffies(vcpu.thread_id))
+            .sum();
+        let host_jiffies = QemuExporter::read_total_host_jiffies()?;
+
+        let factor = match self.vcpu_jiffies.get(guest_uuid) {
+            Some((prev_vcpu_jiffies, prev_host_jiffies)) if host_jiffies > *prev_host_jiffies => {
+                let vcpu_diff = vcpu_jiffies.saturating_sub(*prev_vcpu_jiffies);
+                let host_diff = host_jiffies - prev_host_jiffies;
+                Some(vcpu_diff as f64 / host_diff as f64)
+            }
+            _ => None,
+        };
+        self.vcpu_jiffies.insert(guest_uuid.to_string(), (vcpu_jiffies, host_jiffies));
+        factor
+    }
+
+    /// Reads `utime + stime` (in clock ticks) for host thread `tid`, from
+    /// `/proc/self/task/<tid>/stat`, or else from `/proc/<tid>/stat`.
+    fn read_thread_jiffies(tid: i32) -> Option<u64> {
+        let stat = fs::read_to_string(format!("/proc/self/task/{tid}/stat"))
+            .or_else(|_| {
+                // task ids are unique host-wide, but /proc/self only resolves
+                // them for our own threads, so fall back to scanning by tid
+                // directly under /proc when tid belongs to another process.
+                fs::read_to_string(format!("/proc/{tid}/stat"))
+            })
+            .ok()?;
+        let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+        // utime and stime are fields 14 and 15 of /proc/<pid>/stat, i.e.
+        // indices 11 and 12 once the pid and comm fields were split off.
+        let utime = fields.get(11)?.parse::<u64>().ok()?;
+        let stime = fields.get(12)?.parse::<u64>().ok()?;
+        Some(utime + stime)
+    }
+
+    /// Sums all per-CPU jiffies reported on the first line of `/proc/stat`.
+    fn read_total_host_jiffies() -> Option<u64> {
+        let stat = fs::read_to_string("/proc/stat").ok()?;
+        let line = stat.lines().next()?;
+        let mut fields = line.split_whitespace();
+        fields.next()?; // "cpu"
+        Some(fields.filter_map(|f| f.parse::<u64>().ok()).sum())
+    }
+
     /// Filters 'processes' to match processes that look like qemu/kvm guest processes.
     /// Returns what was found.
     fn filter_qemu_vm_processes(processes: &[&Vec<ProcessRecord>]) -> Vec<Vec<ProcessRecord>> {
@@ -0,0 +1,163 @@
+//! Minimal client for the subset of QMP (the QEMU Machine Protocol) needed to
+//! identify a running guest and its vCPU host threads straight from its own
+//! monitor, instead of guessing from the QEMU process cmdline.
+//!
+//! [DEFAULT_QMP_SOCKET_DIR] points at libvirt's own per-domain monitor
+//! sockets, not one dedicated to scaphandre: libvirtd treats each one as its
+//! exclusive control channel, so every connection this client makes to it is
+//! a second client on a monitor that expects to have exactly one. These
+//! queries are read-only and short-lived, but a deployment that wants to
+//! rule out any contention with libvirt's own use of the monitor should
+//! instead point scaphandre at a QMP socket of its own (e.g. a guest booted
+//! with a second `-qmp` socket).
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Directory under which libvirt creates one subdirectory per domain, each
+/// containing a `monitor.sock` unix socket for that guest's QMP monitor.
+pub const DEFAULT_QMP_SOCKET_DIR: &str = "/var/lib/libvirt/qemu";
+
+#[derive(Debug, Deserialize)]
+struct QmpResponse<T> {
+    #[serde(rename = "return")]
+    data: Option<T>,
+    error: Option<QmpError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QmpError {
+    class: String,
+    desc: String,
+}
+
+/// Returns whether `line` is an asynchronous QMP event (carries an `event`
+/// key) rather than a command reply, without needing to know the reply's
+/// `return` type.
+fn is_event_line(line: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(line)
+        .map(|v| v.get("event").is_some())
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryNameReturn {
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryUuidReturn {
+    #[serde(rename = "UUID")]
+    uuid: String,
+}
+
+/// A single vCPU as reported by `query-cpus-fast`: its guest-visible index
+/// and the host thread id (tid) of the QEMU thread that emulates it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QmpVcpu {
+    #[serde(rename = "cpu-index")]
+    pub cpu_index: i64,
+    #[serde(rename = "thread-id")]
+    pub thread_id: i32,
+}
+
+/// Identity and vCPU thread mapping of a guest, obtained from its own QMP
+/// monitor rather than parsed out of the host process cmdline.
+#[derive(Debug, Clone)]
+pub struct QmpGuestInfo {
+    pub name: Option<String>,
+    pub uuid: String,
+    pub vcpus: Vec<QmpVcpu>,
+}
+
+/// A minimal synchronous client for the handful of QMP commands needed here.
+/// Holds a single connection to one guest's monitor socket.
+///
+/// The read side is kept as one long-lived `BufReader` for the lifetime of
+/// the connection: constructing a fresh one per line would discard whatever
+/// the previous one had already buffered past that line's newline, silently
+/// dropping the next reply (or event) sitting right behind it in the stream.
+pub struct QmpClient {
+    write_stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl QmpClient {
+    /// Connects to the QMP monitor socket at `socket_path` and completes the
+    /// capabilities negotiation handshake (reads the greeting banner, then
+    /// issues `qmp_capabilities`).
+    pub fn connect(socket_path: &Path) -> std::io::Result<QmpClient> {
+        let stream = UnixStream::connect(socket_path)?;
+        stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+        stream.set_write_timeout(Some(Duration::from_millis(500)))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut client = QmpClient { write_stream: stream, reader };
+        let mut greeting = String::new();
+        client.read_line(&mut greeting)?;
+        client.execute_raw("qmp_capabilities")?;
+        Ok(client)
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> std::io::Result<()> {
+        self.reader.read_line(buf)?;
+        Ok(())
+    }
+
+    /// Sends `{"execute": command}` and returns the raw JSON reply line,
+    /// skipping over any asynchronous QMP events QEMU may interleave with
+    /// command replies (these can arrive at any time after the capabilities
+    /// handshake and carry an `event` key rather than `return`/`error`).
+    fn execute_raw(&mut self, command: &str) -> std::io::Result<String> {
+        let request = serde_json::json!({ "execute": command });
+        writeln!(self.write_stream, "{request}")?;
+        loop {
+            let mut line = String::new();
+            self.read_line(&mut line)?;
+            if is_event_line(&line) {
+                continue;
+            }
+            return Ok(line);
+        }
+    }
+
+    fn query<T: serde::de::DeserializeOwned>(&mut self, command: &str) -> std::io::Result<Option<T>> {
+        let line = self.execute_raw(command)?;
+        let response: QmpResponse<T> =
+            serde_json::from_str(&line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if let Some(err) = response.error {
+            warn!("QMP command {} failed: {}: {}", command, err.class, err.desc);
+            return Ok(None);
+        }
+        Ok(response.data)
+    }
+
+    /// Issues `query-name`, `query-uuid` and `query-cpus-fast` and assembles
+    /// the guest info. Returns `None` if the guest doesn't report a UUID
+    /// (which should never happen for a real QEMU instance).
+    pub fn query_guest_info(&mut self) -> std::io::Result<Option<QmpGuestInfo>> {
+        let name = self.query::<QueryNameReturn>("query-name")?.and_then(|r| r.name);
+        let uuid = match self.query::<QueryUuidReturn>("query-uuid")? {
+            Some(r) => r.uuid,
+            None => return Ok(None),
+        };
+        let vcpus = self.query::<Vec<QmpVcpu>>("query-cpus-fast")?.unwrap_or_default();
+        Ok(Some(QmpGuestInfo { name, uuid, vcpus }))
+    }
+}
+
+/// Lists the `monitor.sock` QMP sockets available under `socket_dir`, one per
+/// running guest, as created by libvirt under `/var/lib/libvirt/qemu/<domain>/`.
+pub fn discover_monitor_sockets(socket_dir: &Path) -> Vec<PathBuf> {
+    let mut sockets = vec![];
+    if let Ok(entries) = std::fs::read_dir(socket_dir) {
+        for entry in entries.flatten() {
+            let socket_path = entry.path().join("monitor.sock");
+            if socket_path.exists() {
+                sockets.push(socket_path);
+            }
+        }
+    }
+    sockets
+}
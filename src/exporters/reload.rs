@@ -0,0 +1,129 @@
+//! Watches an exporter's configuration file for changes, either through
+//! filesystem notifications or `SIGHUP`, and hands back the freshly parsed
+//! settings so the exporter can apply them in place instead of restarting
+//! the process -- which would otherwise lose the `Topology`'s accumulated
+//! `energy_uj` counters and `proc_tracker` history.
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+/// The subset of `PrometheusExporter` settings that can be changed live, by
+/// editing the watched config file and sending `SIGHUP` (or simply saving
+/// the file, if inotify support is available).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ReloadableConfig {
+    pub address: String,
+    pub port: String,
+    pub suffix: String,
+    #[serde(default)]
+    pub qemu: bool,
+    #[serde(default)]
+    pub containers: bool,
+}
+
+impl ReloadableConfig {
+    /// Reads and parses the JSON config file at `path`.
+    pub fn load(path: &Path) -> io::Result<ReloadableConfig> {
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Whether moving from `self` to `new` requires rebinding the HTTP
+    /// server, i.e. whether the listen address or port changed.
+    pub fn needs_rebind(&self, new: &ReloadableConfig) -> bool {
+        self.address != new.address || self.port != new.port
+    }
+}
+
+/// Spawns a background thread that watches `config_path` for changes (via
+/// inotify when available, and always on `SIGHUP`), and sends the freshly
+/// reloaded config over the returned channel each time it changes.
+pub fn watch(config_path: PathBuf) -> Receiver<ReloadableConfig> {
+    let (tx, rx) = channel();
+    thread::spawn(move || watch_loop(config_path, tx));
+    rx
+}
+
+fn watch_loop(config_path: PathBuf, tx: Sender<ReloadableConfig>) {
+    let mut signals = match signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]) {
+        Ok(signals) => Some(signals),
+        Err(err) => {
+            warn!("Could not register a SIGHUP handler, config reload on signal is disabled: {err}");
+            None
+        }
+    };
+
+    let mut inotify = match inotify::Inotify::init() {
+        Ok(mut inotify) => {
+            if let Err(err) = set_nonblocking(&inotify) {
+                warn!("Could not set inotify to non-blocking mode, config reload on file change is disabled: {err}");
+                None
+            } else {
+                match inotify
+                    .watches()
+                    .add(&config_path, inotify::WatchMask::MODIFY | inotify::WatchMask::CLOSE_WRITE)
+                {
+                    Ok(_) => Some(inotify),
+                    Err(err) => {
+                        warn!("Could not watch {}, config reload on file change is disabled: {err}", config_path.display());
+                        None
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            warn!("Could not initialize inotify, config reload on file change is disabled: {err}");
+            None
+        }
+    };
+
+    // `inotify`'s fd was made non-blocking above, so `read_events` returns
+    // immediately (with `WouldBlock` when nothing is pending) instead of
+    // blocking the loop until the next filesystem event arrives -- that's
+    // what lets the SIGHUP check and the sleep below run on every pass.
+    let mut buffer = [0; 1024];
+    loop {
+        if let Some(signals) = signals.as_mut() {
+            if signals.pending().next().is_some() {
+                reload(&config_path, &tx);
+            }
+        }
+        if let Some(inotify) = inotify.as_mut() {
+            if let Ok(events) = inotify.read_events(&mut buffer) {
+                if events.count() > 0 {
+                    reload(&config_path, &tx);
+                }
+            }
+        }
+        thread::sleep(std::time::Duration::from_millis(500));
+    }
+}
+
+/// Puts `inotify`'s underlying file descriptor in non-blocking mode. The
+/// `inotify` crate leaves the fd blocking after `init()` and expects callers
+/// who want non-blocking reads to set this themselves, so `watch_loop`'s
+/// `read_events` would otherwise block until a filesystem event arrives
+/// rather than taking part in its polling loop.
+fn set_nonblocking(inotify: &inotify::Inotify) -> nix::Result<()> {
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    let fd = inotify.as_raw_fd();
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+fn reload(config_path: &Path, tx: &Sender<ReloadableConfig>) {
+    match ReloadableConfig::load(config_path) {
+        Ok(config) => {
+            info!("Reloaded exporter configuration from {}", config_path.display());
+            // The receiving end may have been dropped if the exporter is
+            // shutting down; there is nothing useful to do about it here.
+            let _ = tx.send(config);
+        }
+        Err(err) => error!("Failed to reload {}: {}", config_path.display(), err),
+    }
+}